@@ -0,0 +1,54 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use teloxide::dispatching::dialogue::serializer::Json;
+use teloxide::dispatching::dialogue::{Dialogue, SqliteStorage};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::executor::TaskCommand;
+
+/// 对话状态：空闲，或等待用户确认一份待执行的命令计划
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum DialogueState {
+    #[default]
+    Idle,
+    AwaitingConfirmation {
+        commands: Vec<TaskCommand>,
+    },
+}
+
+/// 用 SQLite 而不是 `InMemStorage`：待确认计划是任意 shell 执行前的最后一道
+/// 安全闸，bot 重启/崩溃不应该悄悄把一条 `AwaitingConfirmation` 丢掉，否则用户
+/// 点击确认按钮时只会看到「没有待确认的计划了」，却意识不到计划本身还在生效
+pub type ApprovalStorage = SqliteStorage<Json>;
+pub type ApprovalDialogue = Dialogue<DialogueState, ApprovalStorage>;
+
+pub const CALLBACK_EXECUTE: &str = "approve:execute";
+pub const CALLBACK_EDIT: &str = "approve:edit";
+pub const CALLBACK_CANCEL: &str = "approve:cancel";
+
+/// 构造「✅ 执行 / ✏️ 修改 / ❌ 取消」确认键盘
+pub fn confirmation_keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[
+        InlineKeyboardButton::callback("✅ 执行", CALLBACK_EXECUTE),
+        InlineKeyboardButton::callback("✏️ 修改", CALLBACK_EDIT),
+        InlineKeyboardButton::callback("❌ 取消", CALLBACK_CANCEL),
+    ]])
+}
+
+/// 判断某条命令是否命中强制确认的正则黑名单
+pub fn matches_denylist(cmd: &str, denylist: &[String]) -> bool {
+    denylist.iter().any(|pattern| {
+        Regex::new(pattern)
+            .map(|re| re.is_match(cmd))
+            .unwrap_or(false)
+    })
+}
+
+/// 给定本次计划，判断是否需要先走审批流程再执行
+pub fn needs_confirmation(
+    commands: &[TaskCommand],
+    require_confirmation: bool,
+    denylist: &[String],
+) -> bool {
+    require_confirmation || commands.iter().any(|c| matches_denylist(&c.command, denylist))
+}
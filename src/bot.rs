@@ -1,11 +1,23 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::dispatching::dialogue::serializer::Json;
 use teloxide::prelude::*;
+use teloxide::types::MessageId;
+use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 
+use crate::approval::{
+    confirmation_keyboard, needs_confirmation, ApprovalDialogue, ApprovalStorage, DialogueState,
+    CALLBACK_CANCEL, CALLBACK_EDIT, CALLBACK_EXECUTE,
+};
+use crate::commands::{self, CommandRegistry};
 use crate::config::AppConfig;
 use crate::executor::{CommandResult, Executor, TaskCommand};
-use crate::llm_client::LlmClient;
+use crate::llm_client::{ChatMessage, LlmClient};
+use crate::policy::{self, Policy};
+use crate::session::SessionStore;
 
 fn parse_commands(llm_response: &str) -> Vec<TaskCommand> {
     let json_text = extract_json_array(llm_response);
@@ -45,6 +57,10 @@ fn format_results(commands: &[TaskCommand], results: &[CommandResult]) -> String
         let status = if result.success { "✅" } else { "❌" };
         msg.push_str(&format!("{status} {desc}\n"));
         msg.push_str(&format!("  命令: {}\n", result.command));
+        msg.push_str(&format!("  隔离: {:?}\n", result.isolation));
+        if result.killed_for_output_limit {
+            msg.push_str("  ⚠️ 输出超出限制，已被强制终止\n");
+        }
         if !result.stdout.is_empty() {
             let stdout = truncate(&result.stdout, 500);
             msg.push_str(&format!("  输出:\n{stdout}\n"));
@@ -66,20 +82,185 @@ fn truncate(s: &str, max: usize) -> String {
     }
 }
 
+fn plan_text(commands: &[TaskCommand]) -> String {
+    commands
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{}. {} → `{}`", i + 1, c.description, c.command))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// 两次 `edit_message_text` 之间的最短间隔，避免触发 Telegram 的编辑频率限制
+const EDIT_DEBOUNCE: Duration = Duration::from_millis(800);
+
+/// 汇总执行结果，作为紧凑的历史消息喂回会话，供下一轮 LLM 参考（比如看到失败后调整计划）
+fn compact_summary(commands: &[TaskCommand], results: &[CommandResult]) -> String {
+    let mut summary = String::new();
+    for (i, result) in results.iter().enumerate() {
+        let desc = commands.get(i).map(|c| c.description.as_str()).unwrap_or("未知");
+        let status = if result.success { "成功" } else { "失败" };
+        summary.push_str(&format!("- [{status}] {desc}: {}\n", result.command));
+        if !result.success && !result.stderr.is_empty() {
+            summary.push_str(&format!("  错误: {}\n", truncate(&result.stderr, 200)));
+        }
+    }
+    summary
+}
+
+/// 流式调用 LLM，把增量内容不断汇入同一条「思考中」消息，返回完整响应
+async fn stream_chat_into_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    thinking_msg_id: MessageId,
+    llm: &LlmClient,
+    history: &[ChatMessage],
+    text: &str,
+) -> Result<String> {
+    let mut stream = llm.chat_stream(history, text);
+    let mut content = String::new();
+    let mut last_edit = Instant::now();
+
+    while let Some(chunk) = stream.next().await {
+        let delta = chunk?;
+        content.push_str(&delta);
+
+        if last_edit.elapsed() >= EDIT_DEBOUNCE {
+            bot.edit_message_text(chat_id, thinking_msg_id, format!("🔄 正在分析任务...\n{content}"))
+                .await
+                .ok();
+            last_edit = Instant::now();
+        }
+    }
+
+    bot.edit_message_text(chat_id, thinking_msg_id, format!("🔄 正在分析任务...\n{content}"))
+        .await
+        .ok();
+
+    Ok(content)
+}
+
+/// 执行单条命令并将 stdout/stderr 行持续编辑进同一条进度消息，
+/// 让长耗时命令（构建、下载）在超时前就能看到进展
+async fn run_command_with_progress(
+    bot: &Bot,
+    chat_id: ChatId,
+    executor: &Executor,
+    task: &TaskCommand,
+) -> ResponseResult<CommandResult> {
+    let progress_msg = bot
+        .send_message(chat_id, format!("⏳ {}\n`{}`", task.description, task.command))
+        .await?;
+
+    let (lines_tx, mut lines_rx) = mpsc::unbounded_channel::<String>();
+    let cmd = task.command.clone();
+    let executor = executor;
+
+    let run = async {
+        let cmd = cmd;
+        executor.run_command_streaming(chat_id.0, &cmd, lines_tx).await
+    };
+    tokio::pin!(run);
+
+    let mut tail = String::new();
+    let mut last_edit = Instant::now();
+
+    let result = loop {
+        tokio::select! {
+            line = lines_rx.recv() => {
+                match line {
+                    Some(l) => {
+                        tail.push_str(&l);
+                        tail.push('\n');
+                        if last_edit.elapsed() >= EDIT_DEBOUNCE {
+                            let preview = truncate(&tail, 2000);
+                            bot.edit_message_text(
+                                chat_id,
+                                progress_msg.id,
+                                format!("⏳ {}\n`{}`\n{preview}", task.description, task.command),
+                            )
+                            .await
+                            .ok();
+                            last_edit = Instant::now();
+                        }
+                    }
+                    None => continue,
+                }
+            }
+            result = &mut run => break result,
+        }
+    };
+
+    match result {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            error!(err = %e, cmd = %task.command, "命令执行异常");
+            Ok(CommandResult {
+                command: task.command.clone(),
+                success: false,
+                exit_code: None,
+                stdout: String::new(),
+                stderr: e.to_string(),
+                isolation: executor.isolation_mode(),
+                killed_for_output_limit: false,
+            })
+        }
+    }
+}
+
+async fn execute_and_report(
+    bot: &Bot,
+    chat_id: ChatId,
+    executor: &Executor,
+    session: &SessionStore,
+    commands: &[TaskCommand],
+    echo_result: bool,
+) -> ResponseResult<()> {
+    let mut results = Vec::new();
+    for task in commands {
+        let result = run_command_with_progress(bot, chat_id, executor, task).await?;
+        let success = result.success;
+        results.push(result);
+        if !success {
+            info!("命令失败，停止后续执行");
+            break;
+        }
+    }
+
+    session.push_assistant(chat_id.0, compact_summary(commands, &results));
+
+    if echo_result {
+        let report = format_results(commands, &results);
+        bot.send_message(chat_id, report).await.ok();
+    }
+
+    Ok(())
+}
+
 async fn handle_message(
     bot: Bot,
     msg: Message,
+    dialogue: ApprovalDialogue,
     llm: Arc<LlmClient>,
     executor: Arc<Executor>,
+    registry: Arc<CommandRegistry>,
+    session: Arc<SessionStore>,
     allowed_chats: Vec<i64>,
     echo_result: bool,
+    require_confirmation: bool,
+    confirm_denylist: Vec<String>,
+    policies: Arc<Vec<Policy>>,
 ) -> ResponseResult<()> {
     let chat_id = msg.chat.id;
+    // 用于日志展示的昵称，用户可随意设置，不能作为策略判断依据
     let from = msg
         .from
         .as_ref()
         .map(|u| u.first_name.as_str())
         .unwrap_or("unknown");
+    // 策略引擎绑定的 `user` 必须是 Telegram 稳定、不可由用户自行更改的数字 ID，
+    // 否则任何 "只允许 xxx 用户" 的策略都能靠改昵称绕过
+    let user_id = msg.from.as_ref().map(|u| u.id.0).unwrap_or(0);
     let chat_type = format!("{:?}", msg.chat.kind);
 
     println!("========================================");
@@ -113,12 +294,20 @@ async fn handle_message(
     println!("[处理] 开始处理消息: {}", text);
     info!(chat_id = chat_id.0, text = %text, "收到消息");
 
-    bot.send_message(chat_id, "🔄 正在分析任务...")
-        .await
-        .ok();
+    if let Some(result) = registry.dispatch(&text, chat_id.0, executor.clone()).await {
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(e) => format!("❌ 命令执行失败: {e}"),
+        };
+        bot.send_message(chat_id, reply).await.ok();
+        return Ok(());
+    }
 
-    let commands = match llm.chat(&text).await {
-        Ok(resp) => parse_commands(&resp),
+    let thinking_msg = bot.send_message(chat_id, "🔄 正在分析任务...").await?;
+
+    let history = session.history(chat_id.0);
+    let resp = match stream_chat_into_message(&bot, chat_id, thinking_msg.id, &llm, &history, &text).await {
+        Ok(resp) => resp,
         Err(e) => {
             error!(err = %e, "LLM 调用失败");
             bot.send_message(chat_id, format!("❌ LLM 调用失败: {e}"))
@@ -128,6 +317,11 @@ async fn handle_message(
         }
     };
 
+    session.push_user(chat_id.0, text.clone());
+    session.push_assistant(chat_id.0, resp.clone());
+
+    let commands = parse_commands(&resp);
+
     if commands.is_empty() {
         bot.send_message(chat_id, "ℹ️ 该消息不需要执行任何命令")
             .await
@@ -135,24 +329,89 @@ async fn handle_message(
         return Ok(());
     }
 
-    let plan: String = commands
-        .iter()
-        .enumerate()
-        .map(|(i, c)| format!("{}. {} → `{}`", i + 1, c.description, c.command))
-        .collect::<Vec<_>>()
-        .join("\n");
+    let outcome = policy::apply(&policies, &commands, chat_id.0, user_id as i64);
+    if !outcome.denied.is_empty() {
+        let denied_plan = plan_text(&outcome.denied);
+        bot.send_message(chat_id, format!("🚫 以下命令被策略拒绝:\n{denied_plan}"))
+            .await
+            .ok();
+    }
+    let commands = outcome.allowed;
+
+    if commands.is_empty() {
+        return Ok(());
+    }
+
+    let plan = plan_text(&commands);
+
+    if outcome.needs_confirm || needs_confirmation(&commands, require_confirmation, &confirm_denylist) {
+        dialogue
+            .update(DialogueState::AwaitingConfirmation {
+                commands: commands.clone(),
+            })
+            .await
+            .ok();
+        bot.send_message(chat_id, format!("📝 执行计划（待确认）:\n{plan}"))
+            .reply_markup(confirmation_keyboard())
+            .await
+            .ok();
+        return Ok(());
+    }
+
     bot.send_message(chat_id, format!("📝 执行计划:\n{plan}"))
         .await
         .ok();
 
-    let results = executor.run_commands(&commands).await;
+    execute_and_report(&bot, chat_id, &executor, &session, &commands, echo_result).await
+}
 
-    if echo_result {
-        let report = format_results(&commands, &results);
-        bot.send_message(chat_id, report).await.ok();
-    }
+async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    dialogue: ApprovalDialogue,
+    executor: Arc<Executor>,
+    session: Arc<SessionStore>,
+    echo_result: bool,
+) -> ResponseResult<()> {
+    let data = query.data.clone().unwrap_or_default();
+    bot.answer_callback_query(&query.id).await.ok();
 
-    Ok(())
+    let Some(msg) = &query.message else {
+        return Ok(());
+    };
+    let chat_id = msg.chat().id;
+
+    let state = dialogue.get().await.ok().flatten().unwrap_or_default();
+    let DialogueState::AwaitingConfirmation { commands } = state else {
+        bot.send_message(chat_id, "ℹ️ 没有待确认的计划了").await.ok();
+        return Ok(());
+    };
+
+    dialogue.exit().await.ok();
+
+    match data.as_str() {
+        CALLBACK_EXECUTE => {
+            bot.send_message(chat_id, "▶️ 已确认，开始执行").await.ok();
+            execute_and_report(&bot, chat_id, &executor, &session, &commands, echo_result).await
+        }
+        CALLBACK_EDIT => {
+            bot.send_message(
+                chat_id,
+                "✏️ 请重新发送修改后的需求，我会重新生成执行计划",
+            )
+            .await
+            .ok();
+            Ok(())
+        }
+        CALLBACK_CANCEL => {
+            bot.send_message(chat_id, "❌ 已取消本次执行").await.ok();
+            Ok(())
+        }
+        other => {
+            warn!(data = %other, "未知的回调数据");
+            Ok(())
+        }
+    }
 }
 
 pub async fn run(config: AppConfig) -> Result<()> {
@@ -162,21 +421,46 @@ pub async fn run(config: AppConfig) -> Result<()> {
 
     let llm = Arc::new(LlmClient::new(config.llm.clone()));
     let executor = Arc::new(Executor::new(config.executor.clone()));
+    let session = Arc::new(SessionStore::new(config.llm.max_history_turns));
 
     info!("开始监听 Telegram 消息...");
     info!("Bot Token: {}...", &config.telegram.bot_token[..config.telegram.bot_token.len().min(10)]);
     info!("允许的聊天 ID: {:?}", &config.telegram.allowed_chat_ids);
 
+    let require_confirmation = config.executor.require_confirmation;
+    let confirm_denylist = config.executor.confirm_denylist.clone();
+    let policies = Arc::new(config.executor.policies.clone());
+
     let handler = dptree::entry()
+        .enter_dialogue::<Update, ApprovalStorage, DialogueState>()
         .branch(
             Update::filter_message().endpoint(
                 |bot: Bot,
                  msg: Message,
+                 dialogue: ApprovalDialogue,
                  llm: Arc<LlmClient>,
                  executor: Arc<Executor>,
+                 registry: Arc<CommandRegistry>,
+                 session: Arc<SessionStore>,
                  allowed_chats: Vec<i64>,
-                 echo_result: bool| {
-                    handle_message(bot, msg, llm, executor, allowed_chats, echo_result)
+                 echo_result: bool,
+                 require_confirmation: bool,
+                 confirm_denylist: Vec<String>,
+                 policies: Arc<Vec<Policy>>| {
+                    handle_message(
+                        bot,
+                        msg,
+                        dialogue,
+                        llm,
+                        executor,
+                        registry,
+                        session,
+                        allowed_chats,
+                        echo_result,
+                        require_confirmation,
+                        confirm_denylist,
+                        policies,
+                    )
                 },
             ),
         )
@@ -184,11 +468,42 @@ pub async fn run(config: AppConfig) -> Result<()> {
             Update::filter_channel_post().endpoint(
                 |bot: Bot,
                  msg: Message,
+                 dialogue: ApprovalDialogue,
                  llm: Arc<LlmClient>,
                  executor: Arc<Executor>,
+                 registry: Arc<CommandRegistry>,
+                 session: Arc<SessionStore>,
                  allowed_chats: Vec<i64>,
+                 echo_result: bool,
+                 require_confirmation: bool,
+                 confirm_denylist: Vec<String>,
+                 policies: Arc<Vec<Policy>>| {
+                    handle_message(
+                        bot,
+                        msg,
+                        dialogue,
+                        llm,
+                        executor,
+                        registry,
+                        session,
+                        allowed_chats,
+                        echo_result,
+                        require_confirmation,
+                        confirm_denylist,
+                        policies,
+                    )
+                },
+            ),
+        )
+        .branch(
+            Update::filter_callback_query().endpoint(
+                |bot: Bot,
+                 query: CallbackQuery,
+                 dialogue: ApprovalDialogue,
+                 executor: Arc<Executor>,
+                 session: Arc<SessionStore>,
                  echo_result: bool| {
-                    handle_message(bot, msg, llm, executor, allowed_chats, echo_result)
+                    handle_callback_query(bot, query, dialogue, executor, session, echo_result)
                 },
             ),
         );
@@ -207,9 +522,31 @@ pub async fn run(config: AppConfig) -> Result<()> {
 
     let llm_clone = llm.clone();
     let executor_clone = executor.clone();
+    // 持久化到 SQLite：待确认计划是任意 shell 执行前的最后一道安全闸，
+    // bot 重启/崩溃不应该悄悄把它丢掉
+    let dialogue_storage = ApprovalStorage::open(&config.executor.approval_db_path, Json)
+        .await
+        .with_context(|| {
+            format!(
+                "无法打开审批状态数据库: {}",
+                config.executor.approval_db_path
+            )
+        })?;
+    let registry = commands::default_registry();
 
     Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![llm_clone, executor_clone, allowed_chats, echo_result])
+        .dependencies(dptree::deps![
+            dialogue_storage,
+            llm_clone,
+            executor_clone,
+            registry,
+            session,
+            allowed_chats,
+            echo_result,
+            require_confirmation,
+            confirm_denylist,
+            policies
+        ])
         .default_handler(|upd| async move {
             println!("[默认处理] 收到未匹配的更新类型: {:?}", upd.kind);
             warn!("未处理的更新: {:?}", upd.kind);
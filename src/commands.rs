@@ -0,0 +1,269 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use std::sync::Arc;
+
+use crate::executor::Executor;
+
+/// 内建命令/触发器执行时能看到的上下文
+pub struct CommandCtx<'a> {
+    pub chat_id: i64,
+    pub args: &'a str,
+    pub executor: Arc<Executor>,
+    pub registry: Arc<CommandRegistry>,
+}
+
+/// 前缀命令，如 `/status`、`/cancel`
+#[async_trait]
+pub trait BotCommand: Send + Sync {
+    /// 命令名（含前导 `/`，不含参数）
+    fn name(&self) -> &str;
+    /// 一句话描述，用于 `/help`
+    fn description(&self) -> &str;
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String>;
+}
+
+/// 不依赖固定前缀、按正则匹配整条消息的触发器
+#[async_trait]
+pub trait Trigger: Send + Sync {
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String>;
+}
+
+/// 内建命令注册表：先匹配前缀命令，再匹配正则触发器，都不命中才会交给 LLM
+pub struct CommandRegistry {
+    prefix_commands: Vec<Box<dyn BotCommand>>,
+    triggers: Vec<(Regex, Box<dyn Trigger>)>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self {
+            prefix_commands: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    pub fn register(mut self, command: Box<dyn BotCommand>) -> Self {
+        self.prefix_commands.push(command);
+        self
+    }
+
+    pub fn register_trigger(mut self, pattern: Regex, trigger: Box<dyn Trigger>) -> Self {
+        self.triggers.push((pattern, trigger));
+        self
+    }
+
+    pub fn help_text(&self) -> String {
+        self.prefix_commands
+            .iter()
+            .map(|c| format!("{} - {}", c.name(), c.description()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// 尝试用内建命令处理一条消息；返回 `None` 表示该消息应该继续走 LLM
+    pub async fn dispatch(
+        self: &Arc<Self>,
+        text: &str,
+        chat_id: i64,
+        executor: Arc<Executor>,
+    ) -> Option<Result<String>> {
+        let trimmed = text.trim();
+
+        if trimmed.starts_with('/') {
+            let mut parts = trimmed.splitn(2, char::is_whitespace);
+            let name = parts.next().unwrap_or("");
+            let args = parts.next().unwrap_or("").trim();
+
+            if let Some(command) = self.prefix_commands.iter().find(|c| c.name() == name) {
+                let ctx = CommandCtx {
+                    chat_id,
+                    args,
+                    executor,
+                    registry: self.clone(),
+                };
+                return Some(command.execute(ctx).await);
+            }
+        }
+
+        for (pattern, trigger) in &self.triggers {
+            if pattern.is_match(trimmed) {
+                let ctx = CommandCtx {
+                    chat_id,
+                    args: trimmed,
+                    executor,
+                    registry: self.clone(),
+                };
+                return Some(trigger.execute(ctx).await);
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct StatusCommand;
+
+#[async_trait]
+impl BotCommand for StatusCommand {
+    fn name(&self) -> &str {
+        "/status"
+    }
+
+    fn description(&self) -> &str {
+        "查看当前正在执行的命令"
+    }
+
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String> {
+        match ctx.executor.current_task(ctx.chat_id) {
+            Some(task) => Ok(format!(
+                "⏳ 正在执行: `{}`\n已运行: {:.0} 秒",
+                task.command,
+                task.started_at.elapsed().as_secs_f64()
+            )),
+            None => Ok("ℹ️ 当前没有正在执行的命令".to_string()),
+        }
+    }
+}
+
+struct CancelCommand;
+
+#[async_trait]
+impl BotCommand for CancelCommand {
+    fn name(&self) -> &str {
+        "/cancel"
+    }
+
+    fn description(&self) -> &str {
+        "取消正在执行的命令"
+    }
+
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String> {
+        if ctx.executor.current_task(ctx.chat_id).is_some() {
+            ctx.executor.cancel(ctx.chat_id);
+            Ok("🛑 已发送取消信号".to_string())
+        } else {
+            Ok("ℹ️ 当前没有可取消的命令".to_string())
+        }
+    }
+}
+
+struct HistoryCommand;
+
+#[async_trait]
+impl BotCommand for HistoryCommand {
+    fn name(&self) -> &str {
+        "/history"
+    }
+
+    fn description(&self) -> &str {
+        "查看最近执行过的命令"
+    }
+
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String> {
+        let history = ctx.executor.recent_history(ctx.chat_id, 10);
+        if history.is_empty() {
+            return Ok("ℹ️ 还没有执行过任何命令".to_string());
+        }
+
+        let lines: Vec<String> = history
+            .iter()
+            .map(|r| {
+                let status = if r.success { "✅" } else { "❌" };
+                format!("{status} `{}`", r.command)
+            })
+            .collect();
+        Ok(format!("📜 最近执行的命令:\n{}", lines.join("\n")))
+    }
+}
+
+struct HelpCommand;
+
+#[async_trait]
+impl BotCommand for HelpCommand {
+    fn name(&self) -> &str {
+        "/help"
+    }
+
+    fn description(&self) -> &str {
+        "列出所有内建命令"
+    }
+
+    async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String> {
+        Ok(format!("🛠 内建命令:\n{}", ctx.registry.help_text()))
+    }
+}
+
+/// 内建命令的默认注册表：状态、取消、历史、帮助
+pub fn default_registry() -> Arc<CommandRegistry> {
+    Arc::new(
+        CommandRegistry::new()
+            .register(Box::new(StatusCommand))
+            .register(Box::new(CancelCommand))
+            .register(Box::new(HistoryCommand))
+            .register(Box::new(HelpCommand)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExecutorConfig;
+
+    fn executor() -> Arc<Executor> {
+        Arc::new(Executor::new(ExecutorConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn dispatch_falls_through_to_llm_on_unknown_prefix() {
+        let registry = default_registry();
+        let result = registry.dispatch("/not-a-real-command", 1, executor()).await;
+        assert!(result.is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_status_with_trailing_args_still_matches() {
+        let registry = default_registry();
+        let result = registry
+            .dispatch("/status 多余的参数", 1, executor())
+            .await;
+        assert!(result.is_some());
+        assert_eq!(
+            result.unwrap().unwrap(),
+            "ℹ️ 当前没有正在执行的命令"
+        );
+    }
+
+    struct EchoTrigger;
+
+    #[async_trait]
+    impl Trigger for EchoTrigger {
+        async fn execute(&self, ctx: CommandCtx<'_>) -> Result<String> {
+            Ok(format!("matched: {}", ctx.args))
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatch_matches_trigger_regex() {
+        let registry = Arc::new(
+            CommandRegistry::new().register_trigger(Regex::new(r"^hello\b").unwrap(), Box::new(EchoTrigger)),
+        );
+        let result = registry.dispatch("hello there", 1, executor()).await;
+        assert_eq!(result.unwrap().unwrap(), "matched: hello there");
+    }
+
+    #[tokio::test]
+    async fn dispatch_falls_through_when_no_trigger_matches() {
+        let registry = Arc::new(
+            CommandRegistry::new().register_trigger(Regex::new(r"^hello\b").unwrap(), Box::new(EchoTrigger)),
+        );
+        let result = registry.dispatch("goodbye", 1, executor()).await;
+        assert!(result.is_none());
+    }
+}
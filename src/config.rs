@@ -1,7 +1,9 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+use crate::policy::Policy;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub telegram: TelegramConfig,
@@ -20,24 +22,55 @@ pub struct TelegramConfig {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct LlmConfig {
-    /// OpenAI 兼容 API 的 base URL
-    pub base_url: String,
-    /// API Key
-    pub api_key: String,
-    /// 模型名称
-    pub model: String,
+    /// 具体后端及其鉴权/路由参数，对应 `[llm.backend]` 表
+    ///
+    /// 这里特意用嵌套表而不是 `#[serde(flatten)]` 摊平：flatten 叠加内部打标签
+    /// 的 enum，在 TOML 这类需要 `deserialize_any` 的格式上是出了名的脆弱组合，
+    /// 容易在字段顺序/类型推断上出问题，不值得省这一层 `[llm.backend]`
+    pub backend: LlmProvider,
     /// 系统提示词（可选，有默认值）
     #[serde(default)]
     pub system_prompt: Option<String>,
     /// 最大 token 数
     #[serde(default = "default_max_tokens")]
     pub max_tokens: u32,
+    /// 每个会话保留的历史轮数（一问一答算一轮），超出时从最旧的开始裁剪
+    #[serde(default = "default_max_history_turns")]
+    pub max_history_turns: usize,
+}
+
+/// 支持的 LLM 后端及各自的连接参数，按 `provider` 字段区分
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum LlmProvider {
+    /// OpenAI 兼容 API（`/chat/completions`，Bearer 鉴权）
+    Openai {
+        /// OpenAI 兼容 API 的 base URL
+        base_url: String,
+        /// API Key
+        api_key: String,
+        /// 模型名称
+        model: String,
+    },
+    /// Cloudflare Workers AI（`accounts/{id}/ai/run/{model}`）
+    Cloudflare {
+        /// Cloudflare 账号 ID
+        account_id: String,
+        /// API Token
+        api_token: String,
+        /// 模型名称
+        model: String,
+    },
 }
 
 fn default_max_tokens() -> u32 {
     2048
 }
 
+fn default_max_history_turns() -> usize {
+    6
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ExecutorConfig {
     /// 命令执行的工作目录
@@ -48,6 +81,68 @@ pub struct ExecutorConfig {
     /// 是否在 Telegram 中回显执行结果
     #[serde(default = "default_true")]
     pub echo_result: bool,
+    /// 执行前是否需要在 Telegram 中点击确认（默认开启，这是防止任意 shell 执行的安全闸）
+    #[serde(default = "default_true")]
+    pub require_confirmation: bool,
+    /// 无论 require_confirmation 是否关闭，命中这些正则的命令始终需要人工确认
+    #[serde(default)]
+    pub confirm_denylist: Vec<String>,
+    /// 数据驱动的命令策略：按顺序对每条命令求值，第一条命中的 action 生效
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+    /// 命令执行的隔离方式
+    #[serde(default)]
+    pub isolation: IsolationConfig,
+    /// stdout/stderr 各自的最大字节数，超出则直接杀掉进程，而不是缓冲后在展示时截断
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    /// 待确认计划（`DialogueState`）持久化用的 SQLite 文件路径，重启后还能
+    /// 恢复「等待确认」状态，而不是悄悄丢掉这道执行前的安全闸
+    #[serde(default = "default_approval_db_path")]
+    pub approval_db_path: String,
+}
+
+fn default_max_output_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_approval_db_path() -> String {
+    "approval_state.sqlite".to_string()
+}
+
+/// 命令执行的隔离后端
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IsolationMode {
+    /// 直接在宿主机上用 `sh -c` 执行，不做任何隔离
+    #[default]
+    None,
+    /// 用 nsjail 包一层
+    Nsjail,
+    /// 用 bubblewrap（bwrap）包一层
+    Bwrap,
+    /// 用 `docker run` 包一层
+    Docker,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct IsolationConfig {
+    #[serde(default)]
+    pub mode: IsolationMode,
+    /// 内存上限，格式统一写成 docker 风格（如 "512m"、"1g"）；nsjail 的
+    /// `--rlimit_as` 只认纯数字 MB，由 `build_command` 在 nsjail 分支里换算，
+    /// 不需要在配置里为 nsjail 单独写一份不带单位的数字
+    pub mem_limit: Option<String>,
+    /// CPU 上限，透传给 nsjail（`--cpu_limit`）和 docker（`--cpus`），如 "1.0"；
+    /// bwrap 模式不使用
+    pub cpu_limit: Option<String>,
+    /// 是否以只读根文件系统运行
+    #[serde(default)]
+    pub readonly_rootfs: bool,
+    /// 挂载给沙箱的可写临时工作目录
+    pub scratch_dir: Option<String>,
+    /// docker 模式下使用的镜像
+    pub docker_image: Option<String>,
 }
 
 fn default_timeout() -> u64 {
@@ -64,6 +159,12 @@ impl Default for ExecutorConfig {
             working_dir: None,
             timeout_secs: default_timeout(),
             echo_result: true,
+            require_confirmation: true,
+            confirm_denylist: Vec::new(),
+            policies: Vec::new(),
+            isolation: IsolationConfig::default(),
+            max_output_bytes: default_max_output_bytes(),
+            approval_db_path: default_approval_db_path(),
         }
     }
 }
@@ -1,10 +1,26 @@
 use anyhow::{Context, Result};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
+use tokio::sync::{mpsc, Mutex, Notify};
 use tracing::{error, info};
 
-use crate::config::ExecutorConfig;
+use crate::config::{ExecutorConfig, IsolationConfig, IsolationMode};
+
+/// 最多保留的历史命令结果数量，供 `/history` 查询
+const HISTORY_LIMIT: usize = 20;
+
+/// 当前正在执行的命令，供 `/status` 查询
+#[derive(Debug, Clone)]
+pub struct RunningTask {
+    pub command: String,
+    pub started_at: Instant,
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct TaskCommand {
@@ -22,83 +38,359 @@ pub struct CommandResult {
     pub exit_code: Option<i32>,
     pub stdout: String,
     pub stderr: String,
+    /// 本次执行使用的隔离后端
+    pub isolation: IsolationMode,
+    /// 是否因 stdout/stderr 超出 `max_output_bytes` 而被杀掉
+    pub killed_for_output_limit: bool,
+}
+
+/// nsjail 的 `--rlimit_as` 只接受纯数字 MB（或 `inf`/`def`/`hard`），不支持
+/// docker 风格的单位后缀；把配置里统一的 "512m"/"1g" 换算成 nsjail 能理解的数字
+fn nsjail_mem_limit_mb(mem: &str) -> String {
+    let trimmed = mem.trim();
+    if let Some(mb) = trimmed.strip_suffix(['m', 'M']) {
+        return mb.to_string();
+    }
+    if let Some(gb) = trimmed.strip_suffix(['g', 'G']) {
+        if let Ok(gb) = gb.parse::<u64>() {
+            return (gb * 1024).to_string();
+        }
+    }
+    trimmed.to_string()
+}
+
+/// 按配置的隔离模式，构造实际要 spawn 的命令
+fn build_command(cmd: &str, working_dir: &str, isolation: &IsolationConfig) -> Command {
+    match isolation.mode {
+        IsolationMode::None => {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(cmd).current_dir(working_dir);
+            command
+        }
+        IsolationMode::Nsjail => {
+            let mut command = Command::new("nsjail");
+            // 不传 --disable_clone_newnet：这个 nsjail 选项是关闭新建 net
+            // namespace 的开关，传了反而会让 jail 里的命令拿到宿主机完整的网络
+            // 访问权限，和隔离的初衷正好相反。默认行为（即新建 net/pid/ipc/uts
+            // 等 namespace）就是我们想要的隔离
+            command.arg("-Mo");
+            if let Some(mem) = &isolation.mem_limit {
+                command.arg("--rlimit_as").arg(nsjail_mem_limit_mb(mem));
+            }
+            if let Some(cpu) = &isolation.cpu_limit {
+                command.arg("--cpu_limit").arg(cpu);
+            }
+            if isolation.readonly_rootfs {
+                command.arg("-R").arg("/:/:ro");
+            }
+            if let Some(dir) = &isolation.scratch_dir {
+                command.arg("-B").arg(format!("{dir}:/scratch"));
+            }
+            command.arg("--cwd").arg(working_dir);
+            command.arg("--").arg("sh").arg("-c").arg(cmd);
+            command
+        }
+        IsolationMode::Bwrap => {
+            let mut command = Command::new("bwrap");
+            command.arg("--die-with-parent");
+            // 这几个 --unshare-* 和 readonly_rootfs 无关，必须无条件加上：不加的话
+            // `--bind / /` 给子进程的是和宿主机完全相同的网络/PID/IPC/UTS 视图，
+            // 等于没有任何隔离，即便 readonly_rootfs 没开也不该出现这种情况
+            command.arg("--unshare-net");
+            command.arg("--unshare-pid");
+            command.arg("--unshare-ipc");
+            command.arg("--unshare-uts");
+            if isolation.readonly_rootfs {
+                command.arg("--ro-bind").arg("/").arg("/");
+            } else {
+                command.arg("--bind").arg("/").arg("/");
+            }
+            if let Some(dir) = &isolation.scratch_dir {
+                command.arg("--bind").arg(dir).arg("/scratch");
+            }
+            command.arg("--chdir").arg(working_dir);
+            command.arg("--").arg("sh").arg("-c").arg(cmd);
+            command
+        }
+        IsolationMode::Docker => {
+            let mut command = Command::new("docker");
+            command.arg("run").arg("--rm");
+            if let Some(mem) = &isolation.mem_limit {
+                command.arg("--memory").arg(mem);
+            }
+            if let Some(cpu) = &isolation.cpu_limit {
+                command.arg("--cpus").arg(cpu);
+            }
+            if isolation.readonly_rootfs {
+                command.arg("--read-only");
+            }
+            // 挂载 working_dir 本身：nsjail/bwrap 两种模式都绑定了整个宿主机根
+            // 目录，working_dir 下的文件天然可见；docker 模式此前只挂了
+            // scratch_dir，working_dir 里的文件（比如仓库代码）完全访问不到
+            command.arg("-v").arg(format!("{working_dir}:{working_dir}"));
+            if let Some(dir) = &isolation.scratch_dir {
+                command.arg("-v").arg(format!("{dir}:/scratch"));
+            }
+            command.arg("-w").arg(working_dir);
+            let image = isolation.docker_image.as_deref().unwrap_or("alpine:3.19");
+            command.arg(image).arg("sh").arg("-c").arg(cmd);
+            command
+        }
+    }
 }
 
 pub struct Executor {
     config: ExecutorConfig,
+    /// 按 chat_id 隔离，避免一个聊天的 `/status`、`/cancel`、`/history` 看到
+    /// 或影响到另一个聊天正在执行的命令
+    current: DashMap<i64, RunningTask>,
+    cancel: DashMap<i64, Arc<Notify>>,
+    history: DashMap<i64, VecDeque<CommandResult>>,
 }
 
 impl Executor {
     pub fn new(config: ExecutorConfig) -> Self {
-        Self { config }
-    }
-
-    /// 执行单条命令
-    pub async fn run_command(&self, cmd: &str) -> Result<CommandResult> {
-        info!(cmd = %cmd, "执行命令");
-
-        let working_dir = self
-            .config
-            .working_dir
-            .as_deref()
-            .unwrap_or(".");
-
-        let output = tokio::time::timeout(
-            Duration::from_secs(self.config.timeout_secs),
-            Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .current_dir(working_dir)
-                .output(),
-        )
-        .await
-        .with_context(|| format!("命令超时 ({} 秒): {cmd}", self.config.timeout_secs))?
-        .with_context(|| format!("命令执行失败: {cmd}"))?;
+        Self {
+            config,
+            current: DashMap::new(),
+            cancel: DashMap::new(),
+            history: DashMap::new(),
+        }
+    }
+
+    /// 某个聊天当前正在执行的命令，没有则为 `None`
+    pub fn current_task(&self, chat_id: i64) -> Option<RunningTask> {
+        self.current.get(&chat_id).map(|entry| entry.value().clone())
+    }
+
+    /// 当前配置的隔离后端
+    pub fn isolation_mode(&self) -> IsolationMode {
+        self.config.isolation.mode
+    }
+
+    /// 请求取消某个聊天正在执行的命令（协作式：由 `run_command_streaming` 响应）。
+    /// 用 `notify_one` 而不是 `notify_waiters`：后者不会锁存许可，如果 `/cancel`
+    /// 在 `run_command_streaming` 的 select! 真正开始轮询 `.notified()` 之前就
+    /// 触发（两者都是刚 spawn/建好就抢跑，确实会发生），这次唤醒就会直接丢失，
+    /// 命令会一直跑到超时才被杀掉，而不是立刻响应取消
+    pub fn cancel(&self, chat_id: i64) {
+        self.cancel
+            .entry(chat_id)
+            .or_insert_with(|| Arc::new(Notify::new()))
+            .notify_one();
+    }
+
+    /// 某个聊天最近执行过的命令结果，最多返回 `limit` 条，按从新到旧排列
+    pub fn recent_history(&self, chat_id: i64, limit: usize) -> Vec<CommandResult> {
+        self.history
+            .get(&chat_id)
+            .map(|entry| entry.value().iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn record_history(&self, chat_id: i64, result: CommandResult) {
+        let mut history = self.history.entry(chat_id).or_default();
+        if history.len() == HISTORY_LIMIT {
+            history.pop_front();
+        }
+        history.push_back(result);
+    }
+
+    /// 以流式方式执行单条命令：边读 stdout/stderr 边把每一行推送到 `lines_tx`，
+    /// 这样构建、下载这类长耗时命令能持续反馈进度，而不是超时前的 120 秒沉默。
+    /// 运行状态、取消信号、历史记录都按 `chat_id` 隔离
+    pub async fn run_command_streaming(
+        &self,
+        chat_id: i64,
+        cmd: &str,
+        lines_tx: mpsc::UnboundedSender<String>,
+    ) -> Result<CommandResult> {
+        info!(chat_id, cmd = %cmd, isolation = ?self.config.isolation.mode, "执行命令（流式）");
+
+        let working_dir = self.config.working_dir.as_deref().unwrap_or(".").to_string();
+
+        let mut child = build_command(cmd, &working_dir, &self.config.isolation)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // 超时分支直接返回错误并丢弃 child，靠 kill_on_drop 把超时的进程杀掉
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("命令启动失败: {cmd}"))?;
+
+        let stdout = child.stdout.take().expect("stdout 已配置为 piped");
+        let stderr = child.stderr.take().expect("stderr 已配置为 piped");
+
+        let stdout_buf = Arc::new(Mutex::new(String::new()));
+        let stderr_buf = Arc::new(Mutex::new(String::new()));
+        let output_limit_hit = Arc::new(Notify::new());
+        let max_output_bytes = self.config.max_output_bytes;
+
+        let stdout_task = tokio::spawn(collect_lines(
+            stdout,
+            lines_tx.clone(),
+            stdout_buf.clone(),
+            max_output_bytes,
+            output_limit_hit.clone(),
+        ));
+        let stderr_task = tokio::spawn(collect_lines(
+            stderr,
+            lines_tx,
+            stderr_buf.clone(),
+            max_output_bytes,
+            output_limit_hit.clone(),
+        ));
+
+        self.current.insert(
+            chat_id,
+            RunningTask {
+                command: cmd.to_string(),
+                started_at: Instant::now(),
+            },
+        );
+        let cancel = self
+            .cancel
+            .entry(chat_id)
+            .or_insert_with(|| Arc::new(Notify::new()))
+            .clone();
+
+        let mut killed_for_output_limit = false;
+
+        let status = tokio::select! {
+            status = tokio::time::timeout(Duration::from_secs(self.config.timeout_secs), child.wait()) => {
+                self.current.remove(&chat_id);
+                status
+                    .with_context(|| format!("命令超时 ({} 秒): {cmd}", self.config.timeout_secs))?
+                    .with_context(|| format!("命令执行失败: {cmd}"))?
+            }
+            _ = cancel.notified() => {
+                self.current.remove(&chat_id);
+                child.kill().await.ok();
+                anyhow::bail!("命令已被取消: {cmd}");
+            }
+            _ = output_limit_hit.notified() => {
+                self.current.remove(&chat_id);
+                killed_for_output_limit = true;
+                child.kill().await.ok();
+                child.wait().await.with_context(|| format!("命令执行失败: {cmd}"))?
+            }
+        };
+
+        stdout_task.await.ok();
+        stderr_task.await.ok();
 
         let result = CommandResult {
             command: cmd.to_string(),
-            success: output.status.success(),
-            exit_code: output.status.code(),
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            success: status.success() && !killed_for_output_limit,
+            exit_code: status.code(),
+            stdout: stdout_buf.lock().await.clone(),
+            stderr: stderr_buf.lock().await.clone(),
+            isolation: self.config.isolation.mode,
+            killed_for_output_limit,
         };
 
         if result.success {
-            info!(cmd = %cmd, "命令执行成功");
+            info!(chat_id, cmd = %cmd, "命令执行成功");
         } else {
-            error!(cmd = %cmd, code = ?result.exit_code, stderr = %result.stderr, "命令执行失败");
+            error!(chat_id, cmd = %cmd, code = ?result.exit_code, killed_for_output_limit, stderr = %result.stderr, "命令执行失败");
         }
 
+        self.record_history(chat_id, result.clone());
+
         Ok(result)
     }
+}
 
-    /// 批量执行命令列表
-    pub async fn run_commands(&self, commands: &[TaskCommand]) -> Vec<CommandResult> {
-        let mut results = Vec::new();
-        for task in commands {
-            info!(desc = %task.description, cmd = %task.command, "执行任务");
-            match self.run_command(&task.command).await {
-                Ok(result) => {
-                    let success = result.success;
-                    results.push(result);
-                    if !success {
-                        info!("命令失败，停止后续执行");
-                        break;
-                    }
-                }
-                Err(e) => {
-                    error!(err = %e, "命令执行异常");
-                    results.push(CommandResult {
-                        command: task.command.clone(),
-                        success: false,
-                        exit_code: None,
-                        stdout: String::new(),
-                        stderr: e.to_string(),
-                    });
-                    break;
-                }
-            }
+/// 逐行读取一个输出流，转发给 `lines_tx` 并同时追加到累积缓冲区；
+/// 累积字节数超过 `max_bytes` 时通知 `limit_hit` 并停止读取，而不是无界缓冲
+async fn collect_lines(
+    reader: impl AsyncRead + Unpin,
+    lines_tx: mpsc::UnboundedSender<String>,
+    buf: Arc<Mutex<String>>,
+    max_bytes: usize,
+    limit_hit: Arc<Notify>,
+) {
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let _ = lines_tx.send(line.clone());
+        let mut buf = buf.lock().await;
+        if buf.len() + line.len() + 1 > max_bytes {
+            // 同样用 notify_one 锁存许可，避免 select! 还没开始轮询时输出就已
+            // 超限，导致这次唤醒丢失、进程继续跑到超时
+            limit_hit.notify_one();
+            return;
         }
-        results
+        buf.push_str(&line);
+        buf.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn nsjail_mem_limit_mb_converts_docker_style_suffixes() {
+        assert_eq!(nsjail_mem_limit_mb("512m"), "512");
+        assert_eq!(nsjail_mem_limit_mb("512M"), "512");
+        assert_eq!(nsjail_mem_limit_mb("1g"), "1024");
+        assert_eq!(nsjail_mem_limit_mb("inf"), "inf");
+    }
+
+    #[test]
+    fn build_command_none_runs_sh_directly() {
+        let command = build_command("echo hi", "/work", &IsolationConfig::default());
+        assert_eq!(command.as_std().get_program(), "sh");
+        assert_eq!(args(&command), vec!["-c", "echo hi"]);
+    }
+
+    #[test]
+    fn build_command_nsjail_does_not_disable_net_isolation_and_converts_mem_limit() {
+        let isolation = IsolationConfig {
+            mode: IsolationMode::Nsjail,
+            mem_limit: Some("512m".to_string()),
+            ..IsolationConfig::default()
+        };
+        let command = build_command("echo hi", "/work", &isolation);
+        assert_eq!(command.as_std().get_program(), "nsjail");
+        let args = args(&command);
+        assert!(!args.iter().any(|a| a == "--disable_clone_newnet"));
+        let mem_idx = args.iter().position(|a| a == "--rlimit_as").unwrap();
+        assert_eq!(args[mem_idx + 1], "512");
+    }
+
+    #[test]
+    fn build_command_bwrap_always_unshares_namespaces() {
+        let isolation = IsolationConfig {
+            mode: IsolationMode::Bwrap,
+            ..IsolationConfig::default()
+        };
+        let command = build_command("echo hi", "/work", &isolation);
+        assert_eq!(command.as_std().get_program(), "bwrap");
+        let args = args(&command);
+        for flag in ["--unshare-net", "--unshare-pid", "--unshare-ipc", "--unshare-uts"] {
+            assert!(args.iter().any(|a| a == flag), "missing {flag}");
+        }
+        // readonly_rootfs 没开时也不影响 unshare，只影响 bind 只读与否
+        assert!(args.iter().any(|a| a == "--bind"));
+    }
+
+    #[test]
+    fn build_command_docker_mounts_working_dir() {
+        let isolation = IsolationConfig {
+            mode: IsolationMode::Docker,
+            ..IsolationConfig::default()
+        };
+        let command = build_command("echo hi", "/work", &isolation);
+        assert_eq!(command.as_std().get_program(), "docker");
+        let args = args(&command);
+        assert!(args.iter().any(|a| a == "/work:/work"));
     }
 }
@@ -1,8 +1,52 @@
 use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tracing::{debug, info};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info, warn};
 
-use crate::config::LlmConfig;
+use crate::config::{LlmConfig, LlmProvider};
+
+/// 一条历史对话消息，角色为 "user" 或 "assistant"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+/// 粗略估算 token 数（按约 4 字符 = 1 token），仅用于历史裁剪，不追求精确
+fn estimate_tokens(s: &str) -> usize {
+    s.chars().count() / 4 + 1
+}
+
+/// 把系统提示、历史消息、本轮用户消息拼成请求体数组；当估算 token 数超过预算时，
+/// 从最旧的历史消息开始丢弃，保留系统提示和最新的用户输入
+fn build_messages(
+    system_prompt: &str,
+    history: &[ChatMessage],
+    user_message: &str,
+    budget: usize,
+) -> Vec<Value> {
+    let mut used = estimate_tokens(system_prompt) + estimate_tokens(user_message);
+    let mut start = history.len();
+    for (i, msg) in history.iter().enumerate().rev() {
+        let cost = estimate_tokens(&msg.content);
+        if used + cost > budget {
+            break;
+        }
+        used += cost;
+        start = i;
+    }
+
+    let mut messages = vec![json!({ "role": "system", "content": system_prompt })];
+    for msg in &history[start..] {
+        messages.push(json!({ "role": msg.role, "content": msg.content }));
+    }
+    messages.push(json!({ "role": "user", "content": user_message }));
+    messages
+}
 
 const DEFAULT_SYSTEM_PROMPT: &str = r#"你是一个自动化任务执行代理。用户通过 Telegram 频道发来消息，你需要分析用户的意图，返回要执行的 shell 命令列表。
 
@@ -20,47 +64,54 @@ const DEFAULT_SYSTEM_PROMPT: &str = r#"你是一个自动化任务执行代理
   {"command": "free -m", "description": "检查内存使用"}
 ]"#;
 
-pub struct LlmClient {
-    client: reqwest::Client,
-    config: LlmConfig,
-}
+/// 一个可插拔的 LLM 后端：给定系统提示、历史消息和本轮用户输入，返回完整回复文本
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn complete(&self, system: &str, history: &[ChatMessage], user: &str) -> Result<String>;
 
-impl LlmClient {
-    pub fn new(config: LlmConfig) -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            config,
-        }
+    /// 流式变体：把回复逐段推送到 `tx`。默认实现不支持真正的流式输出，
+    /// 而是等 `complete` 拿到完整结果后作为单个分片发出；支持 SSE 之类协议的
+    /// 后端（如 OpenAI 兼容接口）应覆盖这个方法
+    async fn complete_stream(
+        &self,
+        system: String,
+        history: Vec<ChatMessage>,
+        user: String,
+        tx: mpsc::Sender<Result<String>>,
+    ) {
+        let result = self.complete(&system, &history, &user).await;
+        let _ = tx.send(result).await;
     }
+}
 
-    pub async fn chat(&self, user_message: &str) -> Result<String> {
-        let system_prompt = self
-            .config
-            .system_prompt
-            .as_deref()
-            .unwrap_or(DEFAULT_SYSTEM_PROMPT);
+struct OpenAiBackend {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+    max_tokens: u32,
+}
 
-        let url = format!(
-            "{}/chat/completions",
-            self.config.base_url.trim_end_matches('/')
-        );
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn complete(&self, system: &str, history: &[ChatMessage], user: &str) -> Result<String> {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        // estimate_tokens 算的已经是 token 数，这里不能再乘 4 个字符换算一次
+        let messages = build_messages(system, history, user, self.max_tokens as usize);
 
         let body = json!({
-            "model": self.config.model,
-            "max_tokens": self.config.max_tokens,
-            "messages": [
-                { "role": "system", "content": system_prompt },
-                { "role": "user", "content": user_message },
-            ]
+            "model": self.model,
+            "max_tokens": self.max_tokens,
+            "messages": messages,
         });
 
-        info!(model = %self.config.model, "调用 LLM");
+        info!(model = %self.model, "调用 LLM（OpenAI 兼容）");
         debug!(url = %url, body = %body, "LLM 请求");
 
         let resp = self
             .client
             .post(&url)
-            .header("Authorization", format!("Bearer {}", self.config.api_key))
+            .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
             .json(&body)
             .send()
@@ -76,12 +127,276 @@ impl LlmClient {
         let result: Value = resp.json().await.context("LLM 响应解析失败")?;
         debug!(response = %result, "LLM 响应");
 
-        let content = result
+        Ok(result
             .pointer("/choices/0/message/content")
             .and_then(|v| v.as_str())
             .unwrap_or("")
-            .to_string();
+            .to_string())
+    }
+
+    /// 以 SSE 流式方式调用 LLM，每个 `data:` 帧解出一段增量内容
+    async fn complete_stream(
+        &self,
+        system: String,
+        history: Vec<ChatMessage>,
+        user: String,
+        tx: mpsc::Sender<Result<String>>,
+    ) {
+        let messages = build_messages(&system, &history, &user, self.max_tokens as usize);
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+
+        let body = json!({
+            "model": self.model,
+            "max_tokens": self.max_tokens,
+            "stream": true,
+            "messages": messages,
+        });
+
+        info!(model = %self.model, "调用 LLM（OpenAI 兼容，流式）");
+
+        let resp = match self
+            .client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .header("Content-Type", "application/json")
+            .json(&body)
+            .send()
+            .await
+            .context("LLM 流式请求失败")
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await.unwrap_or_default();
+            let _ = tx
+                .send(Err(anyhow::anyhow!("LLM API 错误 {status}: {text}")))
+                .await;
+            return;
+        }
+
+        let mut byte_stream = resp.bytes_stream();
+        let mut buf = String::new();
+
+        while let Some(chunk) = byte_stream.next().await {
+            let chunk = match chunk.context("读取 SSE 流失败") {
+                Ok(c) => c,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            buf.push_str(&String::from_utf8_lossy(&chunk));
+
+            while let Some(pos) = buf.find("\n\n") {
+                let frame = buf[..pos].to_string();
+                buf.drain(..=pos + 1);
+
+                for line in frame.lines() {
+                    let Some(data) = line.strip_prefix("data: ") else {
+                        continue;
+                    };
+                    if data == "[DONE]" {
+                        return;
+                    }
+                    let event: Value = match serde_json::from_str(data) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!(err = %e, data = %data, "无法解析 SSE 帧");
+                            continue;
+                        }
+                    };
+                    if let Some(delta) = event
+                        .pointer("/choices/0/delta/content")
+                        .and_then(|v| v.as_str())
+                    {
+                        if tx.send(Ok(delta.to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Cloudflare Workers AI 后端：`POST accounts/{account_id}/ai/run/{model}`，
+/// account-id + token 鉴权，回复在 `result.response` 字段里，不支持流式
+struct CloudflareBackend {
+    client: reqwest::Client,
+    account_id: String,
+    api_token: String,
+    model: String,
+    max_tokens: u32,
+}
+
+#[async_trait]
+impl LlmBackend for CloudflareBackend {
+    async fn complete(&self, system: &str, history: &[ChatMessage], user: &str) -> Result<String> {
+        let url = format!(
+            "https://api.cloudflare.com/client/v4/accounts/{}/ai/run/{}",
+            self.account_id, self.model
+        );
+
+        let messages = build_messages(system, history, user, self.max_tokens as usize);
+        let body = json!({ "messages": messages });
+
+        info!(model = %self.model, "调用 LLM（Cloudflare Workers AI）");
+        debug!(url = %url, body = %body, "LLM 请求");
+
+        let resp = self
+            .client
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", self.api_token))
+            .header("Content-Type", "application/json")
+            .json(&body)
+            .send()
+            .await
+            .context("Cloudflare Workers AI 请求失败")?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await.unwrap_or_default();
+            anyhow::bail!("Cloudflare Workers AI 错误 {status}: {text}");
+        }
+
+        let result: Value = resp.json().await.context("Cloudflare Workers AI 响应解析失败")?;
+        debug!(response = %result, "Cloudflare Workers AI 响应");
+
+        Ok(result
+            .pointer("/result/response")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string())
+    }
+}
+
+pub struct LlmClient {
+    backend: std::sync::Arc<dyn LlmBackend>,
+    system_prompt: String,
+}
+
+impl LlmClient {
+    pub fn new(config: LlmConfig) -> Self {
+        let system_prompt = config
+            .system_prompt
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SYSTEM_PROMPT.to_string());
+
+        let backend: std::sync::Arc<dyn LlmBackend> = match config.backend {
+            LlmProvider::Openai {
+                base_url,
+                api_key,
+                model,
+            } => std::sync::Arc::new(OpenAiBackend {
+                client: reqwest::Client::new(),
+                base_url,
+                api_key,
+                model,
+                max_tokens: config.max_tokens,
+            }),
+            LlmProvider::Cloudflare {
+                account_id,
+                api_token,
+                model,
+            } => std::sync::Arc::new(CloudflareBackend {
+                client: reqwest::Client::new(),
+                account_id,
+                api_token,
+                model,
+                max_tokens: config.max_tokens,
+            }),
+        };
+
+        Self {
+            backend,
+            system_prompt,
+        }
+    }
+
+    pub async fn chat(&self, history: &[ChatMessage], user_message: &str) -> Result<String> {
+        self.backend
+            .complete(&self.system_prompt, history, user_message)
+            .await
+    }
+
+    /// 流式调用 LLM；不支持真正流式的后端会退化为一次性返回整段回复
+    pub fn chat_stream(
+        &self,
+        history: &[ChatMessage],
+        user_message: &str,
+    ) -> impl Stream<Item = Result<String>> {
+        let backend = self.backend.clone();
+        let system = self.system_prompt.clone();
+        let history = history.to_vec();
+        let user = user_message.to_string();
+
+        let (tx, rx) = mpsc::channel::<Result<String>>(32);
+
+        tokio::spawn(async move {
+            backend.complete_stream(system, history, user, tx).await;
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn build_messages_keeps_system_and_user_with_no_history() {
+        let messages = build_messages("sys", &[], "hello", 100);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[0]["content"], "sys");
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"], "hello");
+    }
+
+    #[test]
+    fn build_messages_keeps_all_history_within_budget() {
+        let history = vec![msg("user", "a"), msg("assistant", "b")];
+        let messages = build_messages("sys", &history, "hello", 100);
+        // system + 2 条历史 + 本轮 user
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1]["content"], "a");
+        assert_eq!(messages[2]["content"], "b");
+    }
+
+    #[test]
+    fn build_messages_trims_oldest_history_first_when_over_budget() {
+        // 每条历史占 2 个估算 token（chars/4 + 1），budget 只够留最新一条历史
+        let history = vec![msg("user", "oldest"), msg("assistant", "newest")];
+        let budget = estimate_tokens("sys") + estimate_tokens("hello") + estimate_tokens("newest");
+        let messages = build_messages("sys", &history, "hello", budget);
+
+        // system + 最新一条历史 + 本轮 user，最旧的历史被丢弃
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1]["content"], "newest");
+        assert_eq!(messages[2]["content"], "hello");
+    }
 
-        Ok(content)
+    #[test]
+    fn build_messages_always_keeps_system_and_user_even_under_tiny_budget() {
+        let history = vec![msg("user", "a"), msg("assistant", "b")];
+        let messages = build_messages("sys", &history, "hello", 0);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1]["role"], "user");
     }
 }
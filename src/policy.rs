@@ -0,0 +1,214 @@
+use anyhow::{anyhow, Result};
+use rust_lisp::default_env::default_env;
+use rust_lisp::interpreter::eval;
+use rust_lisp::model::{Env, Symbol, Value};
+use rust_lisp::parser::parse;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::rc::Rc;
+use tracing::warn;
+
+use crate::executor::TaskCommand;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+    Confirm,
+}
+
+/// 一条数据驱动的命令策略：`match_expr` 是一段 Lisp 表达式，
+/// 对每个 `TaskCommand` 求值为真时应用 `action`
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub action: PolicyAction,
+    pub match_expr: String,
+}
+
+impl<'de> Deserialize<'de> for Policy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Raw {
+            #[serde(rename = "match")]
+            match_expr: String,
+            action: PolicyAction,
+        }
+
+        let raw = Raw::deserialize(deserializer)?;
+        // 启动时就解析一遍，让 sexpr 语法错误在加载配置时报出来，而不是等到第一次匹配命令
+        parse_expr(&raw.match_expr).map_err(de::Error::custom)?;
+
+        Ok(Policy {
+            action: raw.action,
+            match_expr: raw.match_expr,
+        })
+    }
+}
+
+fn parse_expr(source: &str) -> Result<Value> {
+    parse(source)
+        .next()
+        .ok_or_else(|| anyhow!("策略表达式为空: {source}"))?
+        .map_err(|e| anyhow!("策略表达式解析失败: {source} ({e:?})"))
+}
+
+/// 配置了的策略里最严格的 action，用作求值失败/异常以及没有任何策略命中时的兜底：
+/// 任何一条策略配了 `deny` 就用 `deny`，否则只要配置了策略就至少 `confirm`，
+/// 这样一个写错的 sexpr 或求值错误不会悄悄退化成放行
+fn fail_safe_action(policies: &[Policy]) -> PolicyAction {
+    if policies.iter().any(|p| p.action == PolicyAction::Deny) {
+        PolicyAction::Deny
+    } else {
+        PolicyAction::Confirm
+    }
+}
+
+/// 依次对单个 `TaskCommand` 求值策略列表，返回第一条命中（求值为真）的 action；
+/// 解析/求值出错时，不把该条策略当成「不命中」继续往下走，而是立即按
+/// `fail_safe_action` 处理——这个功能存在的意义就是兜底安全，配置错误或遇到
+/// `rust_lisp` 不支持的写法不应该悄悄放行。所有策略都正常求值为不命中时同样
+/// 返回 `fail_safe_action`；只有完全没配置策略时才直接放行
+pub fn evaluate(policies: &[Policy], cmd: &TaskCommand, chat_id: i64, user_id: i64) -> PolicyAction {
+    if policies.is_empty() {
+        return PolicyAction::Allow;
+    }
+
+    let fail_safe = fail_safe_action(policies);
+
+    for policy in policies {
+        let expr = match parse_expr(&policy.match_expr) {
+            Ok(expr) => expr,
+            Err(e) => {
+                warn!(err = %e, expr = %policy.match_expr, "策略表达式解析失败，按最严格策略兜底");
+                return fail_safe;
+            }
+        };
+
+        let env = Rc::new(RefCell::new(default_env()));
+        env.borrow_mut()
+            .define(Symbol::from("command"), Value::String(cmd.command.clone()));
+        env.borrow_mut()
+            .define(Symbol::from("chat-id"), Value::Int(chat_id));
+        // `user` 绑定的是 Telegram 的数字用户 ID，而不是可随意更改的昵称
+        env.borrow_mut()
+            .define(Symbol::from("user"), Value::Int(user_id));
+
+        match eval(env, &expr) {
+            Ok(Value::Bool(true)) => return policy.action,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(err = ?e, expr = %policy.match_expr, "策略求值失败，按最严格策略兜底");
+                return fail_safe;
+            }
+        }
+    }
+
+    fail_safe
+}
+
+/// 一批命令应用策略后的结果
+pub struct PolicyOutcome {
+    /// 允许进入后续流程的命令（可能仍需人工确认）
+    pub allowed: Vec<TaskCommand>,
+    /// 被拒绝、不会执行的命令
+    pub denied: Vec<TaskCommand>,
+    /// 是否有命令命中了 `confirm`，需要走审批流程
+    pub needs_confirm: bool,
+}
+
+pub fn apply(policies: &[Policy], commands: &[TaskCommand], chat_id: i64, user_id: i64) -> PolicyOutcome {
+    let mut allowed = Vec::new();
+    let mut denied = Vec::new();
+    let mut needs_confirm = false;
+
+    for cmd in commands {
+        match evaluate(policies, cmd, chat_id, user_id) {
+            PolicyAction::Deny => denied.push(cmd.clone()),
+            PolicyAction::Confirm => {
+                needs_confirm = true;
+                allowed.push(cmd.clone());
+            }
+            PolicyAction::Allow => allowed.push(cmd.clone()),
+        }
+    }
+
+    PolicyOutcome {
+        allowed,
+        denied,
+        needs_confirm,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(command: &str) -> TaskCommand {
+        TaskCommand {
+            command: command.to_string(),
+            description: String::new(),
+        }
+    }
+
+    fn policy(action: PolicyAction, match_expr: &str) -> Policy {
+        Policy {
+            action,
+            match_expr: match_expr.to_string(),
+        }
+    }
+
+    #[test]
+    fn evaluate_allows_by_default_with_no_policies() {
+        let action = evaluate(&[], &cmd("ls"), 1, 1);
+        assert_eq!(action, PolicyAction::Allow);
+    }
+
+    #[test]
+    fn evaluate_returns_first_matching_action() {
+        let policies = vec![
+            policy(PolicyAction::Deny, r#"(equal command "rm -rf /")"#),
+            policy(PolicyAction::Confirm, r#"(equal command "systemctl restart x")"#),
+        ];
+        assert_eq!(
+            evaluate(&policies, &cmd("rm -rf /"), 1, 1),
+            PolicyAction::Deny
+        );
+        assert_eq!(
+            evaluate(&policies, &cmd("systemctl restart x"), 1, 1),
+            PolicyAction::Confirm
+        );
+    }
+
+    #[test]
+    fn evaluate_falls_back_to_fail_safe_action_when_nothing_matches() {
+        // 没有一条策略命中时，按约定退化到最严格的已配置 action（这里是 confirm），
+        // 而不是直接放行
+        let policies = vec![policy(PolicyAction::Confirm, r#"(equal command "foo")"#)];
+        assert_eq!(
+            evaluate(&policies, &cmd("bar"), 1, 1),
+            PolicyAction::Confirm
+        );
+    }
+
+    #[test]
+    fn evaluate_fails_closed_on_eval_error() {
+        // 引用一个不存在的符号，求值会报错；不该悄悄放行
+        let policies = vec![policy(PolicyAction::Deny, "(equal command undefined-symbol)")];
+        assert_eq!(evaluate(&policies, &cmd("ls"), 1, 1), PolicyAction::Deny);
+    }
+
+    #[test]
+    fn evaluate_picks_deny_as_fail_safe_over_confirm() {
+        let policies = vec![
+            policy(PolicyAction::Confirm, r#"(equal command "foo")"#),
+            policy(PolicyAction::Deny, "(equal command undefined-symbol)"),
+        ];
+        // 第一条不命中继续往下走，第二条求值出错：兜底动作取配置里最严格的 deny
+        assert_eq!(evaluate(&policies, &cmd("bar"), 1, 1), PolicyAction::Deny);
+    }
+}
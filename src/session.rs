@@ -0,0 +1,58 @@
+use dashmap::DashMap;
+
+use crate::llm_client::ChatMessage;
+
+/// 按 chat_id 维护的多轮对话历史，让 LLM 能看到之前的提问、计划和执行结果
+pub struct SessionStore {
+    sessions: DashMap<i64, Vec<ChatMessage>>,
+    max_turns: usize,
+}
+
+impl SessionStore {
+    pub fn new(max_turns: usize) -> Self {
+        Self {
+            sessions: DashMap::new(),
+            max_turns,
+        }
+    }
+
+    /// 某个会话当前的历史消息（不含本次新的用户输入）
+    pub fn history(&self, chat_id: i64) -> Vec<ChatMessage> {
+        self.sessions
+            .get(&chat_id)
+            .map(|entry| entry.value().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn push_user(&self, chat_id: i64, content: impl Into<String>) {
+        self.push(chat_id, "user", content);
+    }
+
+    pub fn push_assistant(&self, chat_id: i64, content: impl Into<String>) {
+        self.push(chat_id, "assistant", content);
+    }
+
+    fn push(&self, chat_id: i64, role: &str, content: impl Into<String>) {
+        let mut entry = self.sessions.entry(chat_id).or_default();
+        entry.push(ChatMessage {
+            role: role.to_string(),
+            content: content.into(),
+        });
+
+        // 一轮从一条 user 消息开始，包含其后的计划、执行结果摘要等 assistant 消息；
+        // 超出 max_turns 时按整轮从最旧的开始裁剪
+        let turn_starts: Vec<usize> = entry
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.role == "user")
+            .map(|(i, _)| i)
+            .collect();
+        if turn_starts.len() > self.max_turns {
+            let cut = turn_starts
+                .get(turn_starts.len() - self.max_turns)
+                .copied()
+                .unwrap_or(entry.len());
+            entry.drain(0..cut);
+        }
+    }
+}